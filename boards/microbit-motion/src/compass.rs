@@ -0,0 +1,238 @@
+//! Compass heading calculation from magnetometer readings
+use super::{Axes, MagMeasurement};
+
+/// Hard iron offset to subtract from magnetometer readings, in mG (milligauss)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HardIronOffset {
+    /// Offset along the x axis
+    pub x: i32,
+    /// Offset along the y axis
+    pub y: i32,
+    /// Offset along the z axis
+    pub z: i32,
+}
+
+/// Estimate the hard iron offset from samples taken while rotating the board through all
+/// orientations.
+///
+/// The offset is the center of the range seen on each axis. If no samples are given, the offset
+/// is zero.
+pub fn calibrate_hard_iron(samples: &[MagMeasurement]) -> HardIronOffset {
+    let (mut min, mut max) = match samples.first() {
+        Some(first) => (*first, *first),
+        None => return HardIronOffset::default(),
+    };
+    for sample in samples {
+        min.x = min.x.min(sample.x);
+        min.y = min.y.min(sample.y);
+        min.z = min.z.min(sample.z);
+        max.x = max.x.max(sample.x);
+        max.y = max.y.max(sample.y);
+        max.z = max.z.max(sample.z);
+    }
+    HardIronOffset {
+        x: (min.x + max.x) / 2,
+        y: (min.y + max.y) / 2,
+        z: (min.z + max.z) / 2,
+    }
+}
+
+/// Compass heading in degrees, in the range 0 to 360, measured from the magnetometer x axis
+/// towards its y axis.
+///
+/// The `offset` is subtracted from the reading first. When an accelerometer reading is given as
+/// `tilt`, the magnetic field is projected onto the horizontal plane before computing the
+/// heading. Otherwise the board is assumed to be lying flat.
+///
+/// `mag` and `tilt` must use the same right-handed axes, with z pointing down through the board
+/// when it lies flat. This is how the micro:bit's LSM303AGR reports them: lying flat, it reads
+/// about (0, 0, -1000) mg, as an accelerometer at rest measures the reaction to gravity rather
+/// than gravity itself. A flat reading therefore gives the same heading as no `tilt` at all.
+pub fn heading(mag: &MagMeasurement, offset: &HardIronOffset, tilt: Option<Axes>) -> f32 {
+    let mx = (mag.x - offset.x) as f32;
+    let my = (mag.y - offset.y) as f32;
+    let mz = (mag.z - offset.z) as f32;
+
+    let (x, y) = match tilt {
+        Some((ax, ay, az)) => {
+            // Gravity points opposite to the measured acceleration
+            let (gx, gy, gz) = (-ax.0 as f32, -ay.0 as f32, -az.0 as f32);
+            let roll = libm::atan2f(gy, gz);
+            let pitch = libm::atan2f(-gx, libm::sqrtf(gy * gy + gz * gz));
+            let (sin_roll, cos_roll) = (libm::sinf(roll), libm::cosf(roll));
+            let (sin_pitch, cos_pitch) = (libm::sinf(pitch), libm::cosf(pitch));
+            (
+                mx * cos_pitch + my * sin_roll * sin_pitch + mz * cos_roll * sin_pitch,
+                my * cos_roll - mz * sin_roll,
+            )
+        }
+        None => (mx, my),
+    };
+
+    let degrees = libm::atan2f(y, x).to_degrees();
+    if degrees < 0.0 {
+        degrees + 360.0
+    } else {
+        degrees
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::MilliG};
+
+    // Acceleration measured by a board lying flat, see `heading`
+    const FLAT: Axes = (MilliG(0), MilliG(0), MilliG(-1000));
+
+    fn mag(x: i32, y: i32, z: i32) -> MagMeasurement {
+        MagMeasurement { x, y, z }
+    }
+
+    // Difference between two headings, taking the wrap at 360 degrees into account
+    fn assert_heading(expected: f32, actual: f32) {
+        let diff = (expected - actual).rem_euclid(360.0);
+        assert!(
+            diff.min(360.0 - diff) < 0.5,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    // Express a vector given in level axes in the axes of a board rolled about x and then
+    // pitched about y, both in radians.
+    fn tilted((x, y, z): (f32, f32, f32), roll: f32, pitch: f32) -> (f32, f32, f32) {
+        let (sin_roll, cos_roll) = (libm::sinf(roll), libm::cosf(roll));
+        let (sin_pitch, cos_pitch) = (libm::sinf(pitch), libm::cosf(pitch));
+        let (x, z) = (x * cos_pitch - z * sin_pitch, x * sin_pitch + z * cos_pitch);
+        (x, y * cos_roll + z * sin_roll, z * cos_roll - y * sin_roll)
+    }
+
+    #[test]
+    fn test_heading_known_vectors() {
+        let none = HardIronOffset::default();
+        assert_heading(0.0, heading(&mag(200, 0, 400), &none, None));
+        assert_heading(45.0, heading(&mag(200, 200, 400), &none, None));
+        assert_heading(90.0, heading(&mag(0, 200, 400), &none, None));
+        assert_heading(180.0, heading(&mag(-200, 0, 400), &none, None));
+        assert_heading(270.0, heading(&mag(0, -200, 400), &none, None));
+        assert_heading(315.0, heading(&mag(200, -200, -400), &none, None));
+    }
+
+    #[test]
+    fn test_heading_range() {
+        let none = HardIronOffset::default();
+        for (x, y) in [(200, 0), (0, 200), (-200, 1), (-200, -1), (1, -200)] {
+            let h = heading(&mag(x, y, 0), &none, None);
+            assert!((0.0..360.0).contains(&h), "{} out of range", h);
+        }
+    }
+
+    #[test]
+    fn test_heading_subtracts_offset() {
+        let offset = HardIronOffset {
+            x: 150,
+            y: -80,
+            z: 30,
+        };
+        // (350, 120) is (200, 200) once the offset is removed
+        assert_heading(45.0, heading(&mag(350, 120, 430), &offset, None));
+        assert_heading(
+            45.0,
+            heading(&mag(200, 200, 400), &HardIronOffset::default(), None),
+        );
+        // Without the offset the same reading points elsewhere
+        assert!(
+            (heading(&mag(350, 120, 430), &HardIronOffset::default(), None) - 45.0).abs() > 10.0
+        );
+    }
+
+    #[test]
+    fn test_heading_flat_tilt_matches_no_tilt() {
+        let none = HardIronOffset::default();
+        for m in [
+            mag(200, 0, 400),
+            mag(0, 200, -400),
+            mag(-150, 120, 400),
+            mag(90, -310, 0),
+        ] {
+            assert_heading(heading(&m, &none, None), heading(&m, &none, Some(FLAT)));
+        }
+    }
+
+    #[test]
+    fn test_heading_tilt_compensation() {
+        let none = HardIronOffset::default();
+        for expected in [0.0f32, 30.0, 135.0, 250.0] {
+            for (roll, pitch) in [(20.0, 0.0), (0.0, -25.0), (30.0, 15.0), (-15.0, -30.0)] {
+                let (reading, accel) = tilted_reading(expected, roll, pitch);
+                assert_heading(expected, heading(&reading, &none, Some(accel)));
+            }
+        }
+
+        // Ignoring the tilt gives a wrong heading
+        let (reading, _) = tilted_reading(30.0, 30.0, 15.0);
+        let uncompensated = heading(&reading, &none, None);
+        assert!((uncompensated - 30.0).abs() > 10.0, "{}", uncompensated);
+    }
+
+    // Magnetometer and accelerometer readings of a board facing `heading` degrees, tilted by
+    // `roll` and `pitch` degrees. The field is 300mG horizontally with 400mG pointing down, as
+    // in mid-latitudes.
+    fn tilted_reading(heading: f32, roll: f32, pitch: f32) -> (MagMeasurement, Axes) {
+        let angle = heading.to_radians();
+        let field = (300.0 * libm::cosf(angle), 300.0 * libm::sinf(angle), 400.0);
+        let (roll, pitch) = (roll.to_radians(), pitch.to_radians());
+        let (mx, my, mz) = tilted(field, roll, pitch);
+        let (ax, ay, az) = tilted((0.0, 0.0, -1000.0), roll, pitch);
+        (
+            mag(mx as i32, my as i32, mz as i32),
+            (MilliG(ax as i32), MilliG(ay as i32), MilliG(az as i32)),
+        )
+    }
+
+    #[test]
+    fn test_calibrate_hard_iron() {
+        let samples = [
+            mag(300, -50, 20),
+            mag(-100, -50, 20),
+            mag(100, 150, 20),
+            mag(100, -250, 20),
+            mag(100, -50, 220),
+            mag(100, -50, -180),
+        ];
+        assert_eq!(
+            HardIronOffset {
+                x: 100,
+                y: -50,
+                z: 20
+            },
+            calibrate_hard_iron(&samples)
+        );
+    }
+
+    #[test]
+    fn test_calibrate_hard_iron_empty() {
+        assert_eq!(HardIronOffset::default(), calibrate_hard_iron(&[]));
+    }
+
+    #[test]
+    fn test_calibrated_heading() {
+        let offset = HardIronOffset {
+            x: 100,
+            y: -50,
+            z: 20,
+        };
+        let samples = [
+            mag(300, -50, 20),
+            mag(-100, -50, 20),
+            mag(100, 150, 20),
+            mag(100, -250, 20),
+        ];
+        let calibrated = calibrate_hard_iron(&samples);
+        assert_eq!(offset.x, calibrated.x);
+        assert_eq!(offset.y, calibrated.y);
+        assert_heading(90.0, heading(&mag(100, 150, 20), &calibrated, None));
+    }
+}
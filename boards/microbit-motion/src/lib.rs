@@ -0,0 +1,182 @@
+//! Acceleration and compass math for the micro:bit accelerometer and magnetometer
+//!
+//! This crate has no hardware dependencies, so it builds and is tested on the host. The
+//! micro:bit BSP re-exports it from its accelerometer module.
+#![no_std]
+
+use core::ops::{Add, Neg, Sub};
+
+mod compass;
+
+pub use compass::*;
+
+/// Standard gravity in m/s².
+pub const STANDARD_GRAVITY: f32 = 9.80665;
+
+/// Acceleration in milli-g, where 1g is [`STANDARD_GRAVITY`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MilliG(pub i32);
+
+impl MilliG {
+    /// Acceleration in m/s².
+    pub fn to_m_s2(self) -> f32 {
+        self.0 as f32 * STANDARD_GRAVITY / 1000.0
+    }
+}
+
+impl From<i32> for MilliG {
+    fn from(value: i32) -> Self {
+        Self(value)
+    }
+}
+
+impl From<MilliG> for i32 {
+    fn from(value: MilliG) -> Self {
+        value.0
+    }
+}
+
+impl Add for MilliG {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self {
+        Self(self.0 + rhs.0)
+    }
+}
+
+impl Sub for MilliG {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self {
+        Self(self.0 - rhs.0)
+    }
+}
+
+impl Neg for MilliG {
+    type Output = Self;
+    fn neg(self) -> Self {
+        Self(-self.0)
+    }
+}
+
+/// Acceleration along x, y and z.
+pub type Axes = (MilliG, MilliG, MilliG);
+
+/// Magnitude of an acceleration vector in milli-g.
+pub fn magnitude_mg((x, y, z): Axes) -> f32 {
+    let (x, y, z) = (x.0 as f32, y.0 as f32, z.0 as f32);
+    libm::sqrtf(x * x + y * y + z * z)
+}
+
+/// Acceleration along x, y and z in m/s².
+pub fn to_m_s2((x, y, z): Axes) -> (f32, f32, f32) {
+    (x.to_m_s2(), y.to_m_s2(), z.to_m_s2())
+}
+
+/// Magnetometer data in mG (milligauss)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MagMeasurement {
+    /// Magnetic field along the x axis
+    pub x: i32,
+    /// Magnetic field along the y axis
+    pub y: i32,
+    /// Magnetic field along the z axis
+    pub z: i32,
+}
+
+impl MagMeasurement {
+    /// Convert a magnetometer reading in nT (nanotesla) to mG, where 1mG is 100nT.
+    ///
+    /// Values are rounded to the nearest mG, with halves rounded away from zero. This keeps the
+    /// error below the LSM303AGR's 1.5mG resolution.
+    pub fn from_nt(x: i32, y: i32, z: i32) -> Self {
+        let mg = |nt: i32| (nt + nt.signum() * 50) / 100;
+        Self {
+            x: mg(x),
+            y: mg(y),
+            z: mg(z),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(expected: f32, actual: f32) {
+        assert!(
+            (expected - actual).abs() < 1e-3,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_milli_g_to_m_s2() {
+        assert_close(0.0, MilliG(0).to_m_s2());
+        assert_close(STANDARD_GRAVITY, MilliG(1000).to_m_s2());
+        assert_close(-4.903325, MilliG(-500).to_m_s2());
+        assert_close(2.0 * STANDARD_GRAVITY, MilliG(2000).to_m_s2());
+    }
+
+    #[test]
+    fn test_milli_g_arithmetic() {
+        assert_eq!(MilliG(1250), MilliG(1000) + MilliG(250));
+        assert_eq!(MilliG(-250), MilliG(1000) - MilliG(1250));
+        assert_eq!(MilliG(-1000), -MilliG(1000));
+        assert_eq!(MilliG(42), MilliG::from(42));
+        assert_eq!(42, i32::from(MilliG(42)));
+    }
+
+    #[test]
+    fn test_magnitude() {
+        assert_close(0.0, magnitude_mg((MilliG(0), MilliG(0), MilliG(0))));
+        assert_close(1000.0, magnitude_mg((MilliG(0), MilliG(0), MilliG(-1000))));
+        assert_close(
+            1300.0,
+            magnitude_mg((MilliG(300), MilliG(-400), MilliG(1200))),
+        );
+    }
+
+    #[test]
+    fn test_axes_to_m_s2() {
+        let (x, y, z) = to_m_s2((MilliG(1000), MilliG(-250), MilliG(0)));
+        assert_close(STANDARD_GRAVITY, x);
+        assert_close(-STANDARD_GRAVITY / 4.0, y);
+        assert_close(0.0, z);
+    }
+
+    #[test]
+    fn test_mag_from_nt() {
+        assert_eq!(
+            MagMeasurement {
+                x: 123,
+                y: -123,
+                z: 0
+            },
+            MagMeasurement::from_nt(12_345, -12_345, 0)
+        );
+        assert_eq!(
+            MagMeasurement {
+                x: 4915,
+                y: -4915,
+                z: 1
+            },
+            MagMeasurement::from_nt(491_520, -491_520, 100)
+        );
+    }
+
+    #[test]
+    fn test_mag_from_nt_rounding() {
+        let mg = |nt| MagMeasurement::from_nt(nt, 0, 0).x;
+        assert_eq!(0, mg(49));
+        assert_eq!(1, mg(50));
+        assert_eq!(1, mg(149));
+        assert_eq!(2, mg(150));
+        assert_eq!(0, mg(-49));
+        assert_eq!(-1, mg(-50));
+        assert_eq!(-1, mg(-149));
+        assert_eq!(-2, mg(-150));
+    }
+}
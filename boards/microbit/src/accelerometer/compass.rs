@@ -0,0 +1,16 @@
+//! Compass heading calculation from magnetometer readings
+use super::{MagMeasurement, Measurement, MeasurementExt};
+pub use microbit_motion::{calibrate_hard_iron, HardIronOffset};
+
+/// Compass heading in degrees, in the range 0 to 360, measured from the magnetometer x axis
+/// towards its y axis.
+///
+/// The `offset` is subtracted from the reading first. When an accelerometer reading is given as
+/// `tilt`, the magnetic field is projected onto the horizontal plane before computing the
+/// heading. Otherwise the board is assumed to be lying flat. Readings from
+/// [`Accelerometer::accel_data`](super::Accelerometer::accel_data) and
+/// [`Accelerometer::mag_data`](super::Accelerometer::mag_data) can be passed as they are, see
+/// [`microbit_motion::heading`] for the axis convention.
+pub fn heading(mag: &MagMeasurement, offset: &HardIronOffset, tilt: Option<&Measurement>) -> f32 {
+    microbit_motion::heading(mag, offset, tilt.map(MeasurementExt::axes))
+}
@@ -1,27 +1,164 @@
 //! Accelerometer for the micro:bit
-pub use lsm303agr::{AccelOutputDataRate, Measurement};
+pub use {
+    compass::*,
+    lsm303agr::{AccelMode, AccelOutputDataRate, AccelScale, MagOutputDataRate, Measurement},
+    microbit_motion::{MagMeasurement, MilliG, STANDARD_GRAVITY},
+};
 use {
+    embassy_futures::select::{select, Either},
     embassy_nrf::{
         interrupt::SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0,
         peripherals::{P0_08, P0_16, TWISPI0},
         twim, Peripheral,
     },
-    embassy_sync::channel::DynamicSender,
-    embassy_time::{Duration, Ticker},
+    embassy_sync::channel::{DynamicReceiver, DynamicSender},
+    embassy_time::{with_timeout, Duration, Ticker, Timer},
     futures::StreamExt,
-    lsm303agr::{
-        interface::I2cInterface, mode::MagOneShot, AccelMode, Error as LsmError, Lsm303agr, Status,
-    },
+    heapless::Vec,
+    lsm303agr::{interface::I2cInterface, mode::MagOneShot, Error as LsmError, Lsm303agr, Status},
 };
 
+mod compass;
+
 type I2C<'d> = twim::Twim<'d, TWISPI0>;
 
 /// Accelerometer error
 pub type Error = LsmError<twim::Error, ()>;
 
+/// Error returned when the continuous measurement task stops
+#[derive(Debug)]
+pub enum RunError {
+    /// The sensor could not be configured for the requested data rate or range
+    Init(Error),
+    /// Reading from the sensor kept failing after the configured number of retries
+    Read(Error),
+    /// The sensor could not be reconfigured while running
+    Reconfigure(Error),
+    /// The magnetometer kept timing out after the configured number of retries
+    Timeout,
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for RunError {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        match self {
+            Self::Init(e) => defmt::write!(f, "Init({})", defmt::Debug2Format(e)),
+            Self::Read(e) => defmt::write!(f, "Read({})", defmt::Debug2Format(e)),
+            Self::Reconfigure(e) => defmt::write!(f, "Reconfigure({})", defmt::Debug2Format(e)),
+            Self::Timeout => defmt::write!(f, "Timeout"),
+        }
+    }
+}
+
+impl From<MagError> for RunError {
+    fn from(e: MagError) -> Self {
+        match e {
+            MagError::Sensor(e) => Self::Read(e),
+            MagError::Timeout => Self::Timeout,
+        }
+    }
+}
+
+/// Error returned by [`Accelerometer::mag_data`]
+#[derive(Debug)]
+pub enum MagError {
+    /// Communication with the sensor failed
+    Sensor(Error),
+    /// The one-shot measurement was not ready in time, see [`Accelerometer::mag_data`]
+    Timeout,
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for MagError {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        match self {
+            Self::Sensor(e) => defmt::write!(f, "Sensor({})", defmt::Debug2Format(e)),
+            Self::Timeout => defmt::write!(f, "Timeout"),
+        }
+    }
+}
+
+/// Control sent to [`Accelerometer::run_with_control`] or [`Accelerometer::run_batched`] while
+/// sampling
+#[derive(Debug, Clone, Copy)]
+pub enum AccelControl {
+    /// Change the data rate and the sampling interval
+    DataRate(AccelOutputDataRate),
+    /// Change the full-scale range
+    FullScale(AccelScale),
+    /// Stop sampling and return
+    Stop,
+}
+
+impl From<AccelOutputDataRate> for AccelControl {
+    fn from(rate: AccelOutputDataRate) -> Self {
+        Self::DataRate(rate)
+    }
+}
+
+impl From<AccelScale> for AccelControl {
+    fn from(range: AccelScale) -> Self {
+        Self::FullScale(range)
+    }
+}
+
+/// A batch of consecutive accelerometer samples, as produced by [`Accelerometer::run_batched`]
+pub type SampleBatch<const N: usize> = Vec<Measurement, N>;
+
+// Rejects `run_batched::<0>` at compile time, as such a batch could never hold a sample.
+struct NonEmptyBatch<const N: usize>;
+
+impl<const N: usize> NonEmptyBatch<N> {
+    const ASSERT: () = assert!(N > 0, "batches must hold at least one sample");
+}
+
+/// Number of consecutive read failures tolerated by [`Accelerometer::run`] by default
+pub const DEFAULT_READ_RETRIES: usize = 3;
+
+/// Conversions for accelerometer [`Measurement`]s, which are expressed in milli-g.
+pub trait MeasurementExt {
+    /// Create a measurement from x, y and z values in milli-g.
+    fn from_mg(x: i32, y: i32, z: i32) -> Self;
+
+    /// Acceleration along x, y and z.
+    fn axes(&self) -> (MilliG, MilliG, MilliG);
+
+    /// Magnitude of the acceleration vector in milli-g.
+    fn magnitude_mg(&self) -> f32;
+
+    /// Acceleration along x, y and z in m/s².
+    fn to_m_s2(&self) -> (f32, f32, f32);
+}
+
+impl MeasurementExt for Measurement {
+    fn from_mg(x: i32, y: i32, z: i32) -> Self {
+        Measurement { x, y, z }
+    }
+
+    fn axes(&self) -> (MilliG, MilliG, MilliG) {
+        (MilliG(self.x), MilliG(self.y), MilliG(self.z))
+    }
+
+    fn magnitude_mg(&self) -> f32 {
+        microbit_motion::magnitude_mg(self.axes())
+    }
+
+    fn to_m_s2(&self) -> (f32, f32, f32) {
+        microbit_motion::to_m_s2(self.axes())
+    }
+}
+
+/// Magnetometer data rate set by [`Accelerometer::new`]
+pub const DEFAULT_MAG_RATE: MagOutputDataRate = MagOutputDataRate::Hz10;
+
+// Interval between checks for a completed one-shot magnetometer measurement
+const MAG_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
 /// Accelerometer peripheral present on the microbit
 pub struct Accelerometer<'d> {
     sensor: Lsm303agr<I2cInterface<I2C<'d>>, MagOneShot>,
+    read_retries: usize,
+    mag_rate: MagOutputDataRate,
 }
 
 impl<'d> Accelerometer<'d> {
@@ -39,8 +176,36 @@ impl<'d> Accelerometer<'d> {
         sensor.init()?;
         sensor.set_accel_odr(AccelOutputDataRate::Hz10)?;
         sensor.set_accel_mode(AccelMode::Normal)?;
+        sensor.set_mag_odr(DEFAULT_MAG_RATE)?;
+
+        Ok(Self {
+            sensor,
+            read_retries: DEFAULT_READ_RETRIES,
+            mag_rate: DEFAULT_MAG_RATE,
+        })
+    }
+
+    /// Set the number of consecutive read failures [`Accelerometer::run`] will retry before giving up
+    pub fn set_read_retries(&mut self, retries: usize) {
+        self.read_retries = retries;
+    }
+
+    /// Set the accelerometer full-scale range
+    ///
+    /// Readings from [`Accelerometer::accel_data`] are converted to mg by lsm303agr. This crate
+    /// does not scale them itself, and does not check that the conversion follows the selected
+    /// range. To change the range while sampling, send [`AccelControl::FullScale`] to
+    /// [`Accelerometer::run_with_control`].
+    pub fn set_full_scale(&mut self, range: AccelScale) -> Result<(), Error> {
+        self.sensor.set_accel_scale(range)
+    }
 
-        Ok(Self { sensor })
+    /// Set the accelerometer power mode
+    ///
+    /// Low-power, normal and high-resolution modes have 8, 10 and 12 bits of resolution. As with
+    /// the range, converting readings to mg for the selected mode is left to lsm303agr.
+    pub fn set_mode(&mut self, mode: AccelMode) -> Result<(), Error> {
+        self.sensor.set_accel_mode(mode)
     }
 
     /// Return status of accelerometer
@@ -55,29 +220,223 @@ impl<'d> Accelerometer<'d> {
         self.sensor.accel_data()
     }
 
+    /// Return status of magnetometer
+    pub fn mag_status(&mut self) -> Result<Status, Error> {
+        self.sensor.mag_status()
+    }
+
+    /// Trigger a one-shot magnetometer measurement and wait until it is ready
+    ///
+    /// Returned in mG (milligauss). Fails with [`MagError::Timeout`] if the sensor does not report
+    /// data ready within two periods of the magnetometer data rate, which is 200 ms at the
+    /// default 10 Hz and 20 ms at 100 Hz.
+    pub async fn mag_data(&mut self) -> Result<MagMeasurement, MagError> {
+        match with_timeout(mag_timeout(self.mag_rate), self.poll_mag_data()).await {
+            Ok(result) => result.map_err(MagError::Sensor),
+            Err(_) => Err(MagError::Timeout),
+        }
+    }
+
+    // Poll the one-shot measurement until its data is ready.
+    async fn poll_mag_data(&mut self) -> Result<MagMeasurement, Error> {
+        loop {
+            match self.sensor.mag_data() {
+                Ok(data) => return Ok(MagMeasurement::from_nt(data.x, data.y, data.z)),
+                Err(nb::Error::WouldBlock) => Timer::after(MAG_POLL_INTERVAL).await,
+                Err(nb::Error::Other(e)) => return Err(e),
+            }
+        }
+    }
+
     /// Run a continuous task outputing accelerometer data at the configured data rate
+    ///
+    /// A failure to configure the data rate is returned as [`RunError::Init`]. Read errors are
+    /// treated as transient and retried on the next tick, until more than the configured number
+    /// of consecutive reads have failed, in which case [`RunError::Read`] is returned.
     pub async fn run(
         &mut self,
         rate: AccelOutputDataRate,
         sender: DynamicSender<'_, Measurement>,
-    ) -> Result<(), Error> {
-        let delay = match rate {
-            AccelOutputDataRate::Hz1 => Duration::from_millis(1000),
-            AccelOutputDataRate::Hz10 => Duration::from_millis(100),
-            AccelOutputDataRate::Hz25 => Duration::from_millis(40),
-            AccelOutputDataRate::Hz50 => Duration::from_millis(20),
-            AccelOutputDataRate::Hz100 => Duration::from_millis(10),
-            AccelOutputDataRate::Hz200 => Duration::from_millis(5),
-            AccelOutputDataRate::Hz400 => Duration::from_micros(2500),
-            AccelOutputDataRate::Khz1_344 => Duration::from_micros(744),
-            AccelOutputDataRate::Khz1_620LowPower => Duration::from_micros(617),
-            AccelOutputDataRate::Khz5_376LowPower => Duration::from_micros(186),
+    ) -> Result<(), RunError> {
+        self.sensor.set_accel_odr(rate).map_err(RunError::Init)?;
+        let mut ticker = Ticker::every(sample_interval(rate));
+        let mut failures = 0;
+        loop {
+            ticker.next().await;
+            if let Some(data) = self.read_sample(&mut failures)? {
+                let _ = sender.try_send(data);
+            }
+        }
+    }
+
+    /// Run a continuous task outputing accelerometer data, allowing its settings to be changed
+    ///
+    /// Behaves like [`Accelerometer::run`], but applies any [`AccelControl`] received on `control`
+    /// without restarting the task. A new data rate also changes the sampling interval, and
+    /// readings after a new full-scale range remain in mg. A failure to apply a new setting is
+    /// returned as [`RunError::Reconfigure`]. The task returns `Ok(())` on [`AccelControl::Stop`].
+    pub async fn run_with_control(
+        &mut self,
+        rate: AccelOutputDataRate,
+        sender: DynamicSender<'_, Measurement>,
+        control: DynamicReceiver<'_, AccelControl>,
+    ) -> Result<(), RunError> {
+        self.sensor.set_accel_odr(rate).map_err(RunError::Init)?;
+        let mut ticker = Ticker::every(sample_interval(rate));
+        let mut failures = 0;
+        loop {
+            match select(ticker.next(), control.recv()).await {
+                Either::First(_) => {
+                    if let Some(data) = self.read_sample(&mut failures)? {
+                        let _ = sender.try_send(data);
+                    }
+                }
+                Either::Second(AccelControl::Stop) => return Ok(()),
+                Either::Second(control) => self.apply_control(control, &mut ticker)?,
+            }
+        }
+    }
+
+    /// Run a continuous task outputing accelerometer data in batches of `N` samples
+    ///
+    /// Samples are accumulated and sent together once `N` of them have been read, reducing the
+    /// per-sample channel overhead at high data rates. `N` must be at least one. Settings received
+    /// on `control` and errors are handled as in [`Accelerometer::run_with_control`]. Any partial
+    /// batch is sent before the task returns, whether on [`AccelControl::Stop`] or on an error.
+    pub async fn run_batched<const N: usize>(
+        &mut self,
+        rate: AccelOutputDataRate,
+        sender: DynamicSender<'_, SampleBatch<N>>,
+        control: DynamicReceiver<'_, AccelControl>,
+    ) -> Result<(), RunError> {
+        let () = NonEmptyBatch::<N>::ASSERT;
+        self.sensor.set_accel_odr(rate).map_err(RunError::Init)?;
+        let mut ticker = Ticker::every(sample_interval(rate));
+        let mut failures = 0;
+        let mut samples = SampleBatch::new();
+        let result = loop {
+            match select(ticker.next(), control.recv()).await {
+                Either::First(_) => match self.read_sample(&mut failures) {
+                    Ok(Some(data)) => {
+                        let _ = samples.push(data);
+                        if samples.is_full() {
+                            let _ = sender.try_send(core::mem::take(&mut samples));
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => break Err(e),
+                },
+                Either::Second(AccelControl::Stop) => break Ok(()),
+                Either::Second(control) => {
+                    if let Err(e) = self.apply_control(control, &mut ticker) {
+                        break Err(e);
+                    }
+                }
+            }
         };
-        let mut ticker = Ticker::every(delay);
+        if !samples.is_empty() {
+            let _ = sender.try_send(samples);
+        }
+        result
+    }
+
+    /// Run a continuous task outputing magnetometer data at the given data rate
+    ///
+    /// Errors are handled as in [`Accelerometer::run`], with a measurement that times out counting
+    /// as a failed read. If the last failure was a timeout, [`RunError::Timeout`] is returned.
+    pub async fn run_mag(
+        &mut self,
+        rate: MagOutputDataRate,
+        sender: DynamicSender<'_, MagMeasurement>,
+    ) -> Result<(), RunError> {
+        self.sensor.set_mag_odr(rate).map_err(RunError::Init)?;
+        self.mag_rate = rate;
+        let mut ticker = Ticker::every(mag_sample_interval(rate));
+        let mut failures = 0;
         loop {
             ticker.next().await;
-            let data = self.accel_data()?;
-            let _ = sender.try_send(data);
+            match self.mag_data().await {
+                Ok(data) => {
+                    failures = 0;
+                    let _ = sender.try_send(data);
+                }
+                Err(e) => self.read_failed(&mut failures, e.into())?,
+            }
+        }
+    }
+
+    // Apply a new setting received while sampling. Stopping is left to the caller.
+    fn apply_control(
+        &mut self,
+        control: AccelControl,
+        ticker: &mut Ticker,
+    ) -> Result<(), RunError> {
+        match control {
+            AccelControl::DataRate(rate) => {
+                self.sensor
+                    .set_accel_odr(rate)
+                    .map_err(RunError::Reconfigure)?;
+                *ticker = Ticker::every(sample_interval(rate));
+            }
+            AccelControl::FullScale(range) => {
+                self.set_full_scale(range).map_err(RunError::Reconfigure)?;
+            }
+            AccelControl::Stop => {}
+        }
+        Ok(())
+    }
+
+    // Read a single sample, tolerating up to `read_retries` consecutive failures.
+    fn read_sample(&mut self, failures: &mut usize) -> Result<Option<Measurement>, RunError> {
+        match self.accel_data() {
+            Ok(data) => {
+                *failures = 0;
+                Ok(Some(data))
+            }
+            Err(e) => {
+                self.read_failed(failures, RunError::Read(e))?;
+                Ok(None)
+            }
         }
     }
+
+    // Record a failed read, giving up once more than `read_retries` consecutive reads failed.
+    fn read_failed(&self, failures: &mut usize, e: RunError) -> Result<(), RunError> {
+        *failures += 1;
+        if *failures > self.read_retries {
+            Err(e)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn sample_interval(rate: AccelOutputDataRate) -> Duration {
+    match rate {
+        AccelOutputDataRate::Hz1 => Duration::from_millis(1000),
+        AccelOutputDataRate::Hz10 => Duration::from_millis(100),
+        AccelOutputDataRate::Hz25 => Duration::from_millis(40),
+        AccelOutputDataRate::Hz50 => Duration::from_millis(20),
+        AccelOutputDataRate::Hz100 => Duration::from_millis(10),
+        AccelOutputDataRate::Hz200 => Duration::from_millis(5),
+        AccelOutputDataRate::Hz400 => Duration::from_micros(2500),
+        AccelOutputDataRate::Khz1_344 => Duration::from_micros(744),
+        AccelOutputDataRate::Khz1_620LowPower => Duration::from_micros(617),
+        AccelOutputDataRate::Khz5_376LowPower => Duration::from_micros(186),
+    }
+}
+
+fn mag_sample_interval(rate: MagOutputDataRate) -> Duration {
+    match rate {
+        MagOutputDataRate::Hz10 => Duration::from_millis(100),
+        MagOutputDataRate::Hz20 => Duration::from_millis(50),
+        MagOutputDataRate::Hz50 => Duration::from_millis(20),
+        MagOutputDataRate::Hz100 => Duration::from_millis(10),
+    }
+}
+
+// Time to wait for a one-shot magnetometer measurement, assuming the conversion takes up to one
+// period of the data rate. A fixed 100 ms would leave no margin at 10 Hz, so allow two periods.
+fn mag_timeout(rate: MagOutputDataRate) -> Duration {
+    mag_sample_interval(rate) * 2
 }
@@ -0,0 +1,425 @@
+use {
+    core::future::Future,
+    embassy_time::Duration,
+    embedded_hal::{
+        digital::{InputPin, OutputPin},
+        i2c::{Error as I2cError, ErrorKind},
+    },
+    embedded_hal_async::delay::DelayUs,
+};
+
+/// Settings for recovering a stuck I2C bus.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecoveryConfig {
+    /// Maximum number of SCL pulses per recovery attempt. Nine pulses are enough for any slave to
+    /// finish the byte it is sending.
+    pub pulses: usize,
+    /// Half of the SCL clock period used while recovering.
+    pub half_period: Duration,
+    /// Number of times the pulse sequence is repeated before giving up on a stuck SDA.
+    pub attempts: usize,
+    /// Number of times [`with_recovery`] retries a failed transaction, recovering the bus first.
+    pub retries: usize,
+}
+
+impl Default for RecoveryConfig {
+    fn default() -> Self {
+        Self {
+            pulses: 9,
+            // Roughly 100kHz
+            half_period: Duration::from_micros(5),
+            attempts: 1,
+            retries: 1,
+        }
+    }
+}
+
+/// Error returned when an I2C bus could not be recovered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BusRecoveryError {
+    /// SDA is still held low after clocking out the slave.
+    SdaStuckLow,
+    /// Error driving or reading one of the bus pins.
+    Pin,
+    /// Error waiting for half a clock period.
+    Delay,
+}
+
+/// Recover an I2C bus where a slave holds SDA low, typically after a reset in the middle of a
+/// transfer, using the default [`RecoveryConfig`].
+///
+/// SCL is toggled up to nine times until SDA is released, after which a STOP condition is
+/// generated. Both pins must be configured as open-drain outputs that can be read back, and
+/// must not be in use by the I2C peripheral while recovering. `delay` paces the clock, for
+/// example `embassy_time::Delay`.
+pub async fn recover_bus<SCL, SDA, D>(
+    scl: &mut SCL,
+    sda: &mut SDA,
+    delay: &mut D,
+) -> Result<(), BusRecoveryError>
+where
+    SCL: OutputPin,
+    SDA: InputPin + OutputPin,
+    D: DelayUs,
+{
+    recover_bus_with(scl, sda, delay, &RecoveryConfig::default()).await
+}
+
+/// Recover an I2C bus as [`recover_bus`] does, with the given pulse count, timing and number of
+/// attempts.
+pub async fn recover_bus_with<SCL, SDA, D>(
+    scl: &mut SCL,
+    sda: &mut SDA,
+    delay: &mut D,
+    config: &RecoveryConfig,
+) -> Result<(), BusRecoveryError>
+where
+    SCL: OutputPin,
+    SDA: InputPin + OutputPin,
+    D: DelayUs,
+{
+    let half_period = config.half_period.as_micros() as u32;
+    sda.set_high().map_err(|_| BusRecoveryError::Pin)?;
+    let mut released = sda.is_high().map_err(|_| BusRecoveryError::Pin)?;
+    for _ in 0..config.attempts {
+        for _ in 0..config.pulses {
+            if released {
+                break;
+            }
+            scl.set_low().map_err(|_| BusRecoveryError::Pin)?;
+            wait(delay, half_period).await?;
+            scl.set_high().map_err(|_| BusRecoveryError::Pin)?;
+            wait(delay, half_period).await?;
+            released = sda.is_high().map_err(|_| BusRecoveryError::Pin)?;
+        }
+        if released {
+            break;
+        }
+    }
+
+    if !released {
+        warn!("I2C bus recovery failed, SDA stuck low");
+        return Err(BusRecoveryError::SdaStuckLow);
+    }
+
+    // STOP condition: SDA going high while SCL is high
+    scl.set_low().map_err(|_| BusRecoveryError::Pin)?;
+    wait(delay, half_period).await?;
+    sda.set_low().map_err(|_| BusRecoveryError::Pin)?;
+    wait(delay, half_period).await?;
+    scl.set_high().map_err(|_| BusRecoveryError::Pin)?;
+    wait(delay, half_period).await?;
+    sda.set_high().map_err(|_| BusRecoveryError::Pin)?;
+    wait(delay, half_period).await?;
+    Ok(())
+}
+
+async fn wait<D: DelayUs>(delay: &mut D, us: u32) -> Result<(), BusRecoveryError> {
+    delay
+        .delay_us(us)
+        .await
+        .map_err(|_| BusRecoveryError::Delay)
+}
+
+/// Whether an I2C error may be caused by a stuck bus, and recovering the bus may help.
+///
+/// Only bus errors and lost arbitration qualify. Other errors, such as a slave not acknowledging
+/// its address, are not fixed by clocking the bus.
+pub fn is_bus_error<E: I2cError>(e: &E) -> bool {
+    matches!(e.kind(), ErrorKind::Bus | ErrorKind::ArbitrationLoss)
+}
+
+/// Run an I2C transaction, recovering the bus and retrying when it fails with a bus error.
+///
+/// This is meant for sensor drivers, which would otherwise fail every transaction once a slave
+/// holds SDA low. When `transaction` fails with an error for which [`is_bus_error`] holds, it is
+/// retried up to [`RecoveryConfig::retries`] times, calling `recover` before each retry. Any other
+/// error is returned right away, as is the error of the last attempt. A failed recovery is logged
+/// and the transaction is retried anyway.
+///
+/// Recovery needs direct control of SCL and SDA, which the I2C peripheral holds while it is
+/// running. `recover` therefore typically tears the peripheral down to release the pins, calls
+/// [`recover_bus_with`] on them and sets the peripheral up again. Both callbacks can share the
+/// peripheral through a `RefCell`, as they are never called concurrently.
+pub async fn with_recovery<F, FUT, T, E, R, RFUT>(
+    config: &RecoveryConfig,
+    mut transaction: F,
+    mut recover: R,
+) -> Result<T, E>
+where
+    F: FnMut() -> FUT,
+    FUT: Future<Output = Result<T, E>>,
+    E: I2cError,
+    R: FnMut() -> RFUT,
+    RFUT: Future<Output = Result<(), BusRecoveryError>>,
+{
+    let mut retries = config.retries;
+    loop {
+        match transaction().await {
+            Ok(value) => return Ok(value),
+            Err(e) if retries == 0 || !is_bus_error(&e) => return Err(e),
+            Err(_) => {
+                retries -= 1;
+                warn!("I2C transaction failed, recovering bus");
+                if let Err(e) = recover().await {
+                    warn!("I2C bus recovery failed: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use {
+        super::*,
+        core::cell::{Cell, RefCell},
+        embedded_hal::{digital::ErrorType, i2c::NoAcknowledgeSource},
+        std::vec::Vec,
+    };
+
+    #[test]
+    fn test_recover_released_sda() {
+        let bus = TestBus::new(3);
+        let result = futures::executor::block_on(recover_bus_with(
+            &mut TestScl(&bus),
+            &mut TestSda(&bus),
+            &mut NoDelay,
+            &config(),
+        ));
+
+        assert_eq!(Ok(()), result);
+        assert_eq!(3, bus.pulses.get());
+        // STOP condition after the clock pulses
+        assert_eq!(
+            [Event::SclLow, Event::SdaLow, Event::SclHigh, Event::SdaHigh],
+            bus.events.borrow()[bus.events.borrow().len() - 4..]
+        );
+    }
+
+    #[test]
+    fn test_recover_idle_bus() {
+        let bus = TestBus::new(0);
+        let result = futures::executor::block_on(recover_bus_with(
+            &mut TestScl(&bus),
+            &mut TestSda(&bus),
+            &mut NoDelay,
+            &config(),
+        ));
+
+        assert_eq!(Ok(()), result);
+        assert_eq!(0, bus.pulses.get());
+    }
+
+    #[test]
+    fn test_recover_stuck_sda() {
+        let bus = TestBus::new(usize::MAX);
+        let config = RecoveryConfig {
+            attempts: 2,
+            ..config()
+        };
+        let result = futures::executor::block_on(recover_bus_with(
+            &mut TestScl(&bus),
+            &mut TestSda(&bus),
+            &mut NoDelay,
+            &config,
+        ));
+
+        assert_eq!(Err(BusRecoveryError::SdaStuckLow), result);
+        assert_eq!(18, bus.pulses.get());
+        // No STOP condition is generated while SDA is held low
+        assert!(!bus.events.borrow().contains(&Event::SdaLow));
+    }
+
+    #[test]
+    fn test_with_recovery_retries() {
+        let bus = TestBus::new(2);
+        let config = config();
+        let mut failures = 1;
+        let result: Result<u8, TestError> = futures::executor::block_on(with_recovery(
+            &config,
+            || {
+                let result = if failures > 0 {
+                    Err(TestError(ErrorKind::Bus, 0))
+                } else {
+                    Ok(42)
+                };
+                failures -= 1;
+                async move { result }
+            },
+            || recover(&bus, &config),
+        ));
+
+        assert_eq!(Ok(42), result);
+        assert_eq!(2, bus.pulses.get());
+    }
+
+    #[test]
+    fn test_with_recovery_gives_up() {
+        let bus = TestBus::new(usize::MAX);
+        let config = RecoveryConfig {
+            retries: 2,
+            ..config()
+        };
+        let mut calls = 0;
+        let mut recoveries = 0;
+        let result: Result<(), TestError> = futures::executor::block_on(with_recovery(
+            &config,
+            || {
+                calls += 1;
+                let calls = calls;
+                async move { Err(TestError(ErrorKind::ArbitrationLoss, calls)) }
+            },
+            || {
+                recoveries += 1;
+                recover(&bus, &config)
+            },
+        ));
+
+        assert_eq!(Err(TestError(ErrorKind::ArbitrationLoss, 3)), result);
+        assert_eq!(2, recoveries);
+    }
+
+    #[test]
+    fn test_with_recovery_ignores_nack() {
+        let bus = TestBus::new(0);
+        let config = config();
+        let mut calls = 0;
+        let mut recoveries = 0;
+        let nack = ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address);
+        let result: Result<(), TestError> = futures::executor::block_on(with_recovery(
+            &config,
+            || {
+                calls += 1;
+                async move { Err(TestError(nack, 1)) }
+            },
+            || {
+                recoveries += 1;
+                recover(&bus, &config)
+            },
+        ));
+
+        assert_eq!(Err(TestError(nack, 1)), result);
+        assert_eq!(1, calls);
+        assert_eq!(0, recoveries);
+    }
+
+    async fn recover(bus: &TestBus, config: &RecoveryConfig) -> Result<(), BusRecoveryError> {
+        recover_bus_with(&mut TestScl(bus), &mut TestSda(bus), &mut NoDelay, config).await
+    }
+
+    fn config() -> RecoveryConfig {
+        RecoveryConfig {
+            half_period: Duration::from_micros(1),
+            ..Default::default()
+        }
+    }
+
+    // Recovery only needs the pin sequence to be right, so the tests don't wait
+    struct NoDelay;
+
+    impl DelayUs for NoDelay {
+        type Error = core::convert::Infallible;
+
+        async fn delay_us(&mut self, _us: u32) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        async fn delay_ms(&mut self, _ms: u32) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    // An I2C error of the given kind, tagged with the attempt that failed
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct TestError(ErrorKind, usize);
+
+    impl I2cError for TestError {
+        fn kind(&self) -> ErrorKind {
+            self.0
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Event {
+        SclLow,
+        SclHigh,
+        SdaLow,
+        SdaHigh,
+    }
+
+    // A bus where a slave holds SDA low until SCL has been pulsed `release_after` times
+    // while SDA is released.
+    struct TestBus {
+        release_after: usize,
+        pulses: Cell<usize>,
+        sda_low: Cell<bool>,
+        events: RefCell<Vec<Event>>,
+    }
+
+    impl TestBus {
+        fn new(release_after: usize) -> Self {
+            Self {
+                release_after,
+                pulses: Cell::new(0),
+                sda_low: Cell::new(false),
+                events: RefCell::new(Vec::new()),
+            }
+        }
+
+        fn sda_high(&self) -> bool {
+            !self.sda_low.get() && self.pulses.get() >= self.release_after
+        }
+    }
+
+    struct TestScl<'a>(&'a TestBus);
+
+    impl ErrorType for TestScl<'_> {
+        type Error = ();
+    }
+
+    impl OutputPin for TestScl<'_> {
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            self.0.events.borrow_mut().push(Event::SclLow);
+            Ok(())
+        }
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            self.0.events.borrow_mut().push(Event::SclHigh);
+            // The STOP condition raises SCL while SDA is driven low, which doesn't clock the slave
+            if !self.0.sda_low.get() {
+                self.0.pulses.set(self.0.pulses.get() + 1);
+            }
+            Ok(())
+        }
+    }
+
+    struct TestSda<'a>(&'a TestBus);
+
+    impl ErrorType for TestSda<'_> {
+        type Error = ();
+    }
+
+    impl OutputPin for TestSda<'_> {
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            self.0.events.borrow_mut().push(Event::SdaLow);
+            self.0.sda_low.set(true);
+            Ok(())
+        }
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            self.0.events.borrow_mut().push(Event::SdaHigh);
+            self.0.sda_low.set(false);
+            Ok(())
+        }
+    }
+
+    impl InputPin for TestSda<'_> {
+        fn is_high(&self) -> Result<bool, Self::Error> {
+            Ok(self.0.sda_high())
+        }
+        fn is_low(&self) -> Result<bool, Self::Error> {
+            Ok(!self.0.sda_high())
+        }
+    }
+}
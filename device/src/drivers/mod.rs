@@ -5,6 +5,8 @@ pub mod led;
 
 pub mod button;
 
+pub mod i2c;
+
 pub trait ActiveLevel {}
 
 /// Discriminator for inputs/outputs that are active on high state.
@@ -63,6 +63,24 @@ pub enum Error<N, H, C> {
     Protocol,
 }
 
+#[cfg(feature = "defmt")]
+impl<N, H, C> defmt::Format for Error<N, H, C>
+where
+    N: core::fmt::Debug,
+    H: core::fmt::Debug,
+    C: core::fmt::Debug,
+{
+    fn format(&self, f: defmt::Formatter<'_>) {
+        match self {
+            Self::Network(e) => defmt::write!(f, "Network({})", defmt::Debug2Format(&e)),
+            Self::Http(e) => defmt::write!(f, "Http({})", defmt::Debug2Format(&e)),
+            Self::Tls => defmt::write!(f, "Tls"),
+            Self::Codec(e) => defmt::write!(f, "Codec({})", defmt::Debug2Format(&e)),
+            Self::Protocol => defmt::write!(f, "Protocol"),
+        }
+    }
+}
+
 impl<'a, TCP, DNS, const MTU: usize> UpdateService for HttpUpdater<'a, TCP, DNS, MTU>
 where
     TCP: TcpConnect + 'a,
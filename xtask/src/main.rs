@@ -17,6 +17,7 @@ fn main() -> Result<(), anyhow::Error> {
         ["ci_batch"] => ci(true),
         ["check_device"] => check_device(),
         ["test_device"] => test_device(),
+        ["test_crates"] => test_crates(),
         ["test_examples"] => test_examples(),
         ["check", example] => check(&[example]),
         ["build", example] => build(&[example], false),
@@ -73,10 +74,14 @@ static WORKSPACES: &[&str] = &[
     "examples/std",
 ];
 
+// Crates without hardware dependencies, which are tested on the host
+static TESTED_CRATES: &[&str] = &["boards/microbit-motion"];
+
 fn ci(batch: bool) -> Result<(), anyhow::Error> {
     let _e = xshell::pushenv("CI", "true");
 
     test_device()?;
+    test_crates()?;
     check(WORKSPACES)?;
     build(WORKSPACES, batch)?;
     docs()?;
@@ -242,6 +247,10 @@ fn test_device() -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+fn test_crates() -> Result<(), anyhow::Error> {
+    do_crates(TESTED_CRATES, &mut test_crate)
+}
+
 fn do_crates<F: FnMut(PathBuf) -> Result<(), anyhow::Error>>(
     workspaces: &[&str],
     f: &mut F,
@@ -262,6 +271,14 @@ fn check_crate(project_file: PathBuf) -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+fn test_crate(project_file: PathBuf) -> Result<(), anyhow::Error> {
+    println!("Testing {}", project_file.to_str().unwrap_or(""));
+    let _p = xshell::pushd(project_file)?;
+    cmd!("cargo fmt --check").run()?;
+    cmd!("cargo test").run()?;
+    Ok(())
+}
+
 fn build_crate(project_file: PathBuf) -> Result<(), anyhow::Error> {
     println!("Building {}", project_file.to_str().unwrap_or(""));
     let _p = xshell::pushd(project_file)?;